@@ -1,17 +1,21 @@
 // Copyright (c) 2022, Corelight, Inc. All rights reserved.
 
 use std::fs::{metadata, set_permissions, File};
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use byte_unit::Byte;
 use chrono::prelude::*;
 use chrono::OutOfRangeError;
 use chrono::{Duration, NaiveDateTime};
 use clap::Parser;
 use humantime::{format_duration, parse_duration};
+use pcap::Capture;
 use pcap_parser::{create_reader, Block, PcapBlockOwned, PcapError};
 
 const POWER_BITS: u8 = 0x7f;
@@ -19,6 +23,7 @@ const EXPONENT_FLAG_BIT: u8 = 0x80;
 const NANOS_PER_SECOND: u64 = 1_000_000_000;
 const NANOS_PER_MICRO: u32 = 1_000;
 const MICROS_PER_SECOND: f64 = 1e6f64;
+const LIVE_CAPTURE_READ_TIMEOUT_MS: i32 = 100;
 
 /// make_pcapng_timestamp returns a function that will convert the high:low pcapng
 /// timestamp parts into a NaiveDateTime given the value of if_tsresol.
@@ -50,14 +55,69 @@ fn make_pcapng_timestamp(if_tsresol: u8) -> impl Fn(u32, u32) -> NaiveDateTime {
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
-    #[clap(short = 'r', long = "read", value_parser, value_name = "FILE")]
-    input_filename: PathBuf,
+    #[clap(
+        short = 'r',
+        long = "read",
+        value_parser,
+        value_name = "FILE",
+        conflicts_with = "device"
+    )]
+    input_filename: Option<PathBuf>,
+
+    #[clap(
+        short = 'd',
+        long = "device",
+        value_name = "DEVICE",
+        conflicts_with = "input-filename",
+        help = "Capture live from DEVICE instead of reading a file"
+    )]
+    device: Option<String>,
+
+    #[clap(
+        long = "filter",
+        value_name = "BPF",
+        help = "BPF filter applied to live captures (requires --device)"
+    )]
+    filter: Option<String>,
 
     #[clap(short = 'o', long = "output", value_parser, value_name = "FILE")]
     output_filename: PathBuf,
 
     #[clap(short = 'i', long = "interval", parse(try_from_str = parse_duration_arg), value_name = "INTERVAL", default_value = "1 second")]
     minimum_reporting_period: chrono::Duration,
+
+    #[clap(
+        long = "format",
+        arg_enum,
+        value_name = "FORMAT",
+        default_value = "gnuplot",
+        help = "Output format for the per-interval rate records"
+    )]
+    format: OutputFormat,
+
+    #[clap(
+        long = "extract",
+        value_name = "START..END",
+        requires = "write-pcap",
+        help = "Time window (offsets relative to the first packet, or absolute timestamps) of packets to copy into --write-pcap"
+    )]
+    extract: Option<String>,
+
+    #[clap(
+        long = "write-pcap",
+        value_name = "FILE",
+        requires = "extract",
+        help = "Destination capture file for packets in the --extract window"
+    )]
+    write_pcap: Option<PathBuf>,
+}
+
+/// OutputFormat selects the on-disk representation of the accumulated rate rows.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Gnuplot,
+    Csv,
+    Json,
 }
 
 /// parse_duration_arg adapts the str-derived core::time::Duration to a chrono::Duration
@@ -65,57 +125,659 @@ fn parse_duration_arg(arg: &str) -> std::result::Result<Duration, OutOfRangeErro
     Duration::from_std(parse_duration(arg).unwrap())
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// TimeBound is one end of an `--extract` window: either an offset relative to the
+/// first packet seen, or an absolute timestamp.
+enum TimeBound {
+    Relative(Duration),
+    Absolute(NaiveDateTime),
+}
+
+impl TimeBound {
+    fn resolve(&self, first_packet_ts: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            TimeBound::Relative(offset) => first_packet_ts + *offset,
+            TimeBound::Absolute(ts) => *ts,
+        }
+    }
+}
+
+fn parse_time_bound(arg: &str) -> Result<TimeBound> {
+    if let Ok(d) = parse_duration(arg) {
+        return Ok(TimeBound::Relative(Duration::from_std(d)?));
+    }
+
+    let system_time = humantime::parse_rfc3339_weak(arg)
+        .context(format!("Unable to parse {:?} as a duration or timestamp", arg))?;
+    let since_epoch = system_time.duration_since(std::time::UNIX_EPOCH)?;
+
+    Ok(TimeBound::Absolute(
+        NaiveDateTime::from_timestamp_opt(
+            since_epoch.as_secs() as i64,
+            since_epoch.subsec_nanos(),
+        )
+        .unwrap(),
+    ))
+}
+
+/// ExtractWindow is the parsed form of `--extract START..END`; each bound is
+/// resolved against the first packet's timestamp once it's known.
+struct ExtractWindow {
+    start: TimeBound,
+    end: TimeBound,
+}
+
+fn parse_extract_arg(arg: &str) -> Result<ExtractWindow> {
+    let (start, end) = arg
+        .split_once("..")
+        .context("--extract window must be START..END")?;
+
+    Ok(ExtractWindow {
+        start: parse_time_bound(start)?,
+        end: parse_time_bound(end)?,
+    })
+}
+
+/// RateAccumulator tracks the packet/byte counters and timestamps needed to emit
+/// periodic rate rows, independent of whether packets are coming from a file or a
+/// live capture.
+struct RateAccumulator {
+    epoch_ts: NaiveDateTime,
+    first_packet_ts: NaiveDateTime,
+    previous_packet_ts: NaiveDateTime,
+    packet_count: u32,
+    byte_count_wire: u32,
+    byte_count_capture: u32,
+}
+
+impl RateAccumulator {
+    fn new() -> Self {
+        let epoch_ts = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+        RateAccumulator {
+            epoch_ts,
+            first_packet_ts: epoch_ts,
+            previous_packet_ts: epoch_ts,
+            packet_count: 0,
+            byte_count_wire: 0,
+            byte_count_capture: 0,
+        }
+    }
+
+    fn record(&mut self, ts: NaiveDateTime, bytes_wire: u32, bytes_capture: u32) {
+        if self.previous_packet_ts == self.epoch_ts {
+            self.first_packet_ts = ts;
+            self.previous_packet_ts = ts;
+        }
+
+        self.byte_count_capture += bytes_capture;
+        self.byte_count_wire += bytes_wire;
+        self.packet_count += 1;
+    }
+
+    /// Writes a data row for the interval ending at `this_packet_ts` via `sink` if
+    /// the minimum reporting period has elapsed, or, when `force` is set (end of
+    /// input), if the interval holds more than one packet. A zero-length interval
+    /// never produces a row. Resets the interval counters after writing. Returns
+    /// whether a row was actually written, so callers that need to do extra work
+    /// per row (e.g. re-closing a live script after every flush) don't have to
+    /// duplicate the conditions above.
+    fn maybe_report(
+        &mut self,
+        sink: &mut dyn RowSink,
+        writer: &mut dyn Write,
+        this_packet_ts: NaiveDateTime,
+        minimum_reporting_period: Duration,
+        force: bool,
+    ) -> Result<bool> {
+        let elapsed_since_last_packet = this_packet_ts - self.previous_packet_ts;
+
+        // A zero-length interval (e.g. a run of Simple Packet Blocks, which carry
+        // forward the previous timestamp rather than advancing it) has no
+        // meaningful rate; computing one would divide by zero. Skip the row
+        // rather than emit `inf`/`NaN` into the plot or the CSV/JSON output.
+        if elapsed_since_last_packet > Duration::zero()
+            && (elapsed_since_last_packet >= minimum_reporting_period
+                || (force && self.packet_count > 1))
+        {
+            let elapsed_since_last_packet_secs =
+                elapsed_since_last_packet.num_nanoseconds().unwrap() as f64 / 1e+9f64;
+            let elapsed_since_first_packet_secs = (this_packet_ts - self.first_packet_ts)
+                .num_microseconds()
+                .unwrap() as f64
+                / MICROS_PER_SECOND;
+
+            let rate_packets = f64::from(self.packet_count) / elapsed_since_last_packet_secs;
+            let rate_wire_bytes =
+                f64::from(self.byte_count_wire) / elapsed_since_last_packet_secs;
+            let rate_capture_bytes =
+                f64::from(self.byte_count_capture) / elapsed_since_last_packet_secs;
+
+            sink.write_row(
+                writer,
+                elapsed_since_first_packet_secs,
+                rate_packets,
+                rate_wire_bytes,
+                rate_capture_bytes,
+            )?;
+
+            self.previous_packet_ts = this_packet_ts;
+            self.packet_count = 0;
+            self.byte_count_wire = 0;
+            self.byte_count_capture = 0;
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+}
+
+/// RowSink abstracts over the on-disk representation of the accumulated rate
+/// rows (gnuplot script, CSV, or JSON), so the accumulation loop in
+/// `run_file_capture`/`run_live_capture` stays format-agnostic.
+trait RowSink {
+    /// Writes whatever precedes the first row (a gnuplot script header, a CSV
+    /// header row, a JSON array's opening bracket, ...).
+    fn write_header(&mut self, writer: &mut dyn Write, source_description: &str) -> Result<()>;
+
+    /// Writes a single interval's worth of rates.
+    fn write_row(
+        &mut self,
+        writer: &mut dyn Write,
+        elapsed_since_first_packet_secs: f64,
+        rate_packets: f64,
+        rate_wire_bytes: f64,
+        rate_capture_bytes: f64,
+    ) -> Result<()>;
+
+    /// Writes whatever follows the last row (a gnuplot plot command, a JSON
+    /// array's closing bracket, ...). `title` is only meaningful to formats that
+    /// render a plot.
+    fn finish(&mut self, writer: &mut dyn Write, title: &str) -> Result<()>;
+
+    /// Whether the generated file is a script that should be made executable.
+    fn is_executable(&self) -> bool {
+        false
+    }
+}
+
+/// GnuplotSink renders the original `$data`/`plot` gnuplot script.
+struct GnuplotSink;
+
+impl RowSink for GnuplotSink {
+    fn write_header(&mut self, writer: &mut dyn Write, source_description: &str) -> Result<()> {
+        write!(writer, "#!/usr/bin/env -S gnuplot -p\n#\n")?;
+
+        writeln!(
+            writer,
+            "# Generated with plotcap (https://github.com/corelight/plotcap)"
+        )?;
+        writeln!(writer, "# Input: {}", source_description)?;
+        write!(writer, "# Date: {}\n\n", Utc::now())?;
+
+        writeln!(writer, "$data << EOD")?;
+
+        Ok(())
+    }
+
+    fn write_row(
+        &mut self,
+        writer: &mut dyn Write,
+        elapsed_since_first_packet_secs: f64,
+        rate_packets: f64,
+        rate_wire_bytes: f64,
+        rate_capture_bytes: f64,
+    ) -> Result<()> {
+        writeln!(
+            writer,
+            "{} {:.2} {:.2} {:.2}",
+            elapsed_since_first_packet_secs, rate_packets, rate_wire_bytes, rate_capture_bytes
+        )?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write, title: &str) -> Result<()> {
+        write!(
+            writer,
+            "EOD
+
+set title '{}'
+set xlabel 'Time'
+set ylabel 'Packet rate'
+set y2label 'Data rate'
+set format y '%.0s%cpps'
+set format y2 '%.0s%cbps'
+set ytics nomirror
+set y2tics nomirror
+set xtics time format '%tH:%tM:%tS'
+set xtics rotate by -45
+plot    $data u 1:2 with lines axis x1y1 title 'Packets/s', \\
+        $data u 1:($3*8) with lines axis x1y2 title 'Bits/s on the wire', \\
+        $data u 1:($4*8) with points axis x1y2 title 'Bits/s captured'
+pause mouse close\n",
+            title
+        )?;
+
+        Ok(())
+    }
+
+    fn is_executable(&self) -> bool {
+        true
+    }
+}
+
+/// CsvSink renders a plain CSV table with a header row.
+struct CsvSink;
+
+impl RowSink for CsvSink {
+    fn write_header(&mut self, writer: &mut dyn Write, _source_description: &str) -> Result<()> {
+        writeln!(
+            writer,
+            "elapsed_since_first_packet_secs,rate_packets,rate_wire_bytes,rate_capture_bytes"
+        )?;
+
+        Ok(())
+    }
+
+    fn write_row(
+        &mut self,
+        writer: &mut dyn Write,
+        elapsed_since_first_packet_secs: f64,
+        rate_packets: f64,
+        rate_wire_bytes: f64,
+        rate_capture_bytes: f64,
+    ) -> Result<()> {
+        writeln!(
+            writer,
+            "{},{:.2},{:.2},{:.2}",
+            elapsed_since_first_packet_secs, rate_packets, rate_wire_bytes, rate_capture_bytes
+        )?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self, _writer: &mut dyn Write, _title: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// JsonSink renders a JSON array of per-interval rate objects.
+struct JsonSink {
+    wrote_first_row: bool,
+}
+
+impl JsonSink {
+    fn new() -> Self {
+        JsonSink {
+            wrote_first_row: false,
+        }
+    }
+}
+
+impl RowSink for JsonSink {
+    fn write_header(&mut self, writer: &mut dyn Write, _source_description: &str) -> Result<()> {
+        write!(writer, "[")?;
+
+        Ok(())
+    }
+
+    fn write_row(
+        &mut self,
+        writer: &mut dyn Write,
+        elapsed_since_first_packet_secs: f64,
+        rate_packets: f64,
+        rate_wire_bytes: f64,
+        rate_capture_bytes: f64,
+    ) -> Result<()> {
+        if self.wrote_first_row {
+            write!(writer, ",")?;
+        }
+        self.wrote_first_row = true;
+
+        write!(
+            writer,
+            "\n  {{\"elapsed_since_first_packet_secs\": {}, \"rate_packets\": {:.2}, \"rate_wire_bytes\": {:.2}, \"rate_capture_bytes\": {:.2}}}",
+            elapsed_since_first_packet_secs, rate_packets, rate_wire_bytes, rate_capture_bytes
+        )?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write, _title: &str) -> Result<()> {
+        if self.wrote_first_row {
+            writeln!(writer)?;
+        }
+        writeln!(writer, "]")?;
+
+        Ok(())
+    }
+}
+
+fn make_output_executable(output_filename: &Path) -> Result<()> {
+    let mut perms = metadata(output_filename)
+        .context(format!(
+            "Unable to get file permissions for {}",
+            output_filename.display()
+        ))?
+        .permissions();
+
+    perms.set_mode(0o755);
+
+    set_permissions(output_filename, perms).context(format!(
+        "Unable to set file permissions for {}",
+        output_filename.display()
+    ))
+}
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAPNG_BLOCK_TYPE_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_BLOCK_TYPE_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_BLOCK_TYPE_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// pcapng_ticks is the inverse of `make_pcapng_timestamp`: it encodes a
+/// NaiveDateTime back into the 64-bit (ts_high:ts_low) tick value for a given
+/// if_tsresol.
+fn pcapng_ticks(ts: NaiveDateTime, if_tsresol: u8) -> u64 {
+    let exponent = if_tsresol & POWER_BITS;
+    let flag = if_tsresol & EXPONENT_FLAG_BIT == EXPONENT_FLAG_BIT;
+    let secs = ts.timestamp() as u64;
+    let nsecs = ts.timestamp_subsec_nanos() as u64;
+
+    if flag {
+        let divisor = 2u64.pow(exponent as u32);
+        (secs << exponent) | (nsecs * divisor / NANOS_PER_SECOND)
+    } else {
+        let divisor = 10u64.pow(exponent as u32);
+        secs * divisor + (nsecs * divisor) / NANOS_PER_SECOND
+    }
+}
+
+fn write_legacy_pcap_header(writer: &mut impl Write, snaplen: u32, linktype: i32) -> Result<()> {
+    writer.write_all(&PCAP_MAGIC_MICROS.to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?; // version_major
+    writer.write_all(&4u16.to_le_bytes())?; // version_minor
+    writer.write_all(&0i32.to_le_bytes())?; // thiszone
+    writer.write_all(&0u32.to_le_bytes())?; // sigfigs
+    writer.write_all(&snaplen.to_le_bytes())?;
+    writer.write_all(&(linktype as u32).to_le_bytes())?;
+
+    Ok(())
+}
+
+fn write_legacy_pcap_packet(
+    writer: &mut impl Write,
+    ts: NaiveDateTime,
+    caplen: u32,
+    origlen: u32,
+    data: &[u8],
+) -> Result<()> {
+    writer.write_all(&(ts.timestamp() as u32).to_le_bytes())?;
+    writer.write_all(&ts.timestamp_subsec_micros().to_le_bytes())?;
+    writer.write_all(&caplen.to_le_bytes())?;
+    writer.write_all(&origlen.to_le_bytes())?;
+    writer.write_all(data)?;
+
+    Ok(())
+}
+
+fn write_pcapng_section_header(writer: &mut impl Write) -> Result<()> {
+    let block_total_length: u32 = 28;
+    let section_length: i64 = -1; // unknown
+
+    writer.write_all(&PCAPNG_BLOCK_TYPE_SECTION_HEADER.to_le_bytes())?;
+    writer.write_all(&block_total_length.to_le_bytes())?;
+    writer.write_all(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?; // version_major
+    writer.write_all(&0u16.to_le_bytes())?; // version_minor
+    writer.write_all(&section_length.to_le_bytes())?;
+    writer.write_all(&block_total_length.to_le_bytes())?;
+
+    Ok(())
+}
+
+// pcapng Interface Description Block option codes (Section 4.2 of the pcapng spec).
+const PCAPNG_OPT_IF_TSRESOL: u16 = 9;
+const PCAPNG_OPT_END_OF_OPTIONS: u16 = 0;
+
+fn write_pcapng_interface_description(
+    writer: &mut impl Write,
+    linktype: i32,
+    snaplen: u32,
+    if_tsresol: u8,
+) -> Result<()> {
+    // 20 fixed bytes + an if_tsresol option (4-byte header, 4-byte padded value)
+    // + the end-of-options option (4-byte header, no value).
+    let block_total_length: u32 = 32;
+
+    writer.write_all(&PCAPNG_BLOCK_TYPE_INTERFACE_DESCRIPTION.to_le_bytes())?;
+    writer.write_all(&block_total_length.to_le_bytes())?;
+    writer.write_all(&(linktype as u16).to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?; // reserved
+    writer.write_all(&snaplen.to_le_bytes())?;
+
+    // if_tsresol option: without this, readers assume the pcapng default
+    // resolution (microseconds), misdecoding any other-resolution interface's
+    // timestamps on re-read.
+    writer.write_all(&PCAPNG_OPT_IF_TSRESOL.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&[if_tsresol, 0, 0, 0])?; // value, then padding to 4 bytes
+
+    writer.write_all(&PCAPNG_OPT_END_OF_OPTIONS.to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+
+    writer.write_all(&block_total_length.to_le_bytes())?;
+
+    Ok(())
+}
+
+fn write_pcapng_enhanced_packet(
+    writer: &mut impl Write,
+    if_id: u32,
+    ts: NaiveDateTime,
+    if_tsresol: u8,
+    caplen: u32,
+    origlen: u32,
+    data: &[u8],
+) -> Result<()> {
+    let padding = (4 - (data.len() % 4)) % 4;
+    // 8 fixed 4-byte fields (type, total_length, if_id, ts_high, ts_low, caplen,
+    // origlen, and the trailing repeated total_length) plus the padded payload.
+    let block_total_length = (32 + data.len() + padding) as u32;
+    let ticks = pcapng_ticks(ts, if_tsresol);
+
+    writer.write_all(&PCAPNG_BLOCK_TYPE_ENHANCED_PACKET.to_le_bytes())?;
+    writer.write_all(&block_total_length.to_le_bytes())?;
+    writer.write_all(&if_id.to_le_bytes())?;
+    writer.write_all(&((ticks >> 32) as u32).to_le_bytes())?;
+    writer.write_all(&((ticks & 0xFFFF_FFFF) as u32).to_le_bytes())?;
+    writer.write_all(&caplen.to_le_bytes())?;
+    writer.write_all(&origlen.to_le_bytes())?;
+    writer.write_all(data)?;
+    writer.write_all(&vec![0u8; padding])?;
+    writer.write_all(&block_total_length.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// PacketExtractor copies the raw bytes (timestamp/length/payload) of every packet
+/// whose timestamp falls within the `--extract` window out to `--write-pcap`,
+/// reconstructing a minimal standalone capture file around them. For pcapng
+/// input, each interface's Interface Description Block is written into the
+/// output section the first time a matching packet references it, before that
+/// packet's own Enhanced Packet Block.
+///
+/// A source pcapng file can contain more than one Section Header Block, and
+/// `if_id` numbering restarts at 0 within each one (see `pcapng_interfaces` in
+/// `run_file_capture`), so a single flat set of "interfaces already written"
+/// would conflate interface 0 of one section with interface 0 of the next.
+/// Instead we mirror the source's section boundaries into the output: each
+/// time a packet arrives from a source section we haven't copied from yet, we
+/// emit a fresh Section Header Block and start that section's interface
+/// bookkeeping over from scratch.
+struct PacketExtractor {
+    writer: BufWriter<File>,
+    window_start: NaiveDateTime,
+    window_end: NaiveDateTime,
+    header_written: bool,
+    current_section: Option<u32>,
+    // Source if_id -> if_id of its IDB in the current output section.
+    written_interfaces: std::collections::HashMap<u32, u32>,
+}
+
+impl PacketExtractor {
+    fn new(output_filename: &Path, window_start: NaiveDateTime, window_end: NaiveDateTime) -> Result<Self> {
+        let outfile = File::create(output_filename).context(format!(
+            "Unable to open extract output file {}",
+            output_filename.display()
+        ))?;
+
+        Ok(PacketExtractor {
+            writer: BufWriter::new(outfile),
+            window_start,
+            window_end,
+            header_written: false,
+            current_section: None,
+            written_interfaces: std::collections::HashMap::new(),
+        })
+    }
+
+    fn in_window(&self, ts: NaiveDateTime) -> bool {
+        ts >= self.window_start && ts <= self.window_end
+    }
+
+    fn write_legacy_packet(
+        &mut self,
+        linktype: i32,
+        snaplen: u32,
+        ts: NaiveDateTime,
+        caplen: u32,
+        origlen: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        if !self.header_written {
+            write_legacy_pcap_header(&mut self.writer, snaplen, linktype)?;
+            self.header_written = true;
+        }
+
+        write_legacy_pcap_packet(&mut self.writer, ts, caplen, origlen, data)
+    }
+
+    /// Writes an Enhanced Packet Block for interface `if_id` of source section
+    /// `section`, first copying that interface's Interface Description Block into
+    /// the output section if this is the first packet referencing it. `interfaces`
+    /// is the (linktype, snaplen, if_tsresol) table built up from the IDBs seen so
+    /// far in the current source section. The written block's if_id is the
+    /// interface's position among the IDBs of the output section.
+    fn write_ng_packet(
+        &mut self,
+        section: u32,
+        if_id: u32,
+        interfaces: &[(i32, u32, u8)],
+        ts: NaiveDateTime,
+        caplen: u32,
+        origlen: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        // Packet references an interface whose IDB never arrived (malformed, but
+        // chunk0-2 deliberately tolerates exactly this on the timestamp path);
+        // there's nothing to describe the interface with, so skip extracting
+        // this packet instead of panicking.
+        let (linktype, snaplen, if_tsresol) = match interfaces.get(if_id as usize) {
+            Some(&entry) => entry,
+            None => return Ok(()),
+        };
+
+        if self.current_section != Some(section) {
+            write_pcapng_section_header(&mut self.writer)?;
+            self.current_section = Some(section);
+            self.written_interfaces.clear();
+        }
+
+        let out_if_id = match self.written_interfaces.get(&if_id) {
+            Some(&out_if_id) => out_if_id,
+            None => {
+                let out_if_id = self.written_interfaces.len() as u32;
+                write_pcapng_interface_description(&mut self.writer, linktype, snaplen, if_tsresol)?;
+                self.written_interfaces.insert(if_id, out_if_id);
+                out_if_id
+            }
+        };
+
+        write_pcapng_enhanced_packet(&mut self.writer, out_if_id, ts, if_tsresol, caplen, origlen, data)
+    }
 
-    let infile = File::open(&cli.input_filename).context(format!(
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// run_file_capture drives the rate accumulator from a pcap/pcapng file, matching
+/// the previous (file-only) behavior of `main`.
+fn run_file_capture(
+    cli: &Cli,
+    input_filename: &Path,
+    sink: &mut dyn RowSink,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let infile = File::open(input_filename).context(format!(
         "Unable to open input file {}",
-        &cli.input_filename.display()
+        input_filename.display()
     ))?;
 
     let mut reader = create_reader(65536, &infile).context(format!(
         "Unable to read input file {}",
-        cli.input_filename.display()
+        input_filename.display()
     ))?;
 
-    let outfile = File::create(&cli.output_filename).context(format!(
-        "Unable to open output file {}",
-        cli.output_filename.display()
-    ))?;
-
-    let mut writer = BufWriter::new(&outfile);
+    sink.write_header(writer, &input_filename.display().to_string())?;
 
-    let epoch_ts: NaiveDateTime = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
-    let mut this_packet_ts = epoch_ts;
-    let mut first_packet_ts = epoch_ts;
-    let mut previous_packet_ts = epoch_ts;
-    let mut packet_count: u32 = 0;
-    let mut byte_count_wire: u32 = 0;
-    let mut byte_count_capture: u32 = 0;
+    let mut accumulator = RateAccumulator::new();
+    let mut this_packet_ts = accumulator.epoch_ts;
     let mut eof = false;
     let mut file_type = "unknown";
 
-    let mut pcapng_timestamp = make_pcapng_timestamp(6u8);
+    // Per-interface timestamp converters, indexed by interface_id (the order in
+    // which Interface Description Blocks appear within the current section). A
+    // Section Header Block restarts interface numbering, so the vector is cleared
+    // whenever one is seen.
+    let mut pcapng_timestamp_converters: Vec<Box<dyn Fn(u32, u32) -> NaiveDateTime>> = Vec::new();
 
-    write!(&mut writer, "#!/usr/bin/env -S gnuplot -p\n#\n")?;
+    // (linktype, snaplen, if_tsresol) per pcapng interface_id, in IDB order;
+    // cleared alongside `pcapng_timestamp_converters` on a new section.
+    let mut pcapng_interfaces: Vec<(i32, u32, u8)> = Vec::new();
 
-    writeln!(
-        &mut writer,
-        "# Generated with plotcap (https://github.com/corelight/plotcap)"
-    )?;
-    writeln!(
-        &mut writer,
-        "# Input file: {}",
-        cli.input_filename.display()
-    )?;
-    write!(&mut writer, "# Date: {}\n\n", Utc::now())?;
+    // Snaplen of the most recently described interface. Simple Packet Blocks carry
+    // no interface_id of their own (the pcapng spec only allows them when the
+    // section has a single interface), so we just track the latest one seen.
+    let mut current_snaplen: u32 = 0;
+
+    // Counts Section Header Blocks seen so far, so --extract can tell packets
+    // from distinct source sections apart even though their if_id numbering
+    // restarts at 0 in each one (see PacketExtractor).
+    let mut pcapng_section: u32 = 0;
 
-    writeln!(&mut writer, "$data << EOD")?;
+    // Legacy (non-ng) files declare a single linktype/snaplen in their global
+    // header, read once up front.
+    let mut legacy_linktype: i32 = 1;
+    let mut legacy_snaplen: u32 = 262_144;
+
+    let extract_window = cli.extract.as_deref().map(parse_extract_arg).transpose()?;
+    let mut extractor: Option<PacketExtractor> = None;
 
     loop {
         match reader.next() {
             Ok((offset, block)) => {
-                let (packet_bytes_wire, packet_bytes_capture, ts) = match block {
+                enum ExtractPayload<'a> {
+                    Legacy(&'a [u8]),
+                    Ng(u32, &'a [u8]),
+                    // SimplePacket blocks carry no interface_id, so they're not
+                    // eligible for --extract.
+                    None,
+                }
+
+                let (packet_bytes_wire, packet_bytes_capture, ts, payload) = match block {
                     PcapBlockOwned::Legacy(b) => (
                         b.origlen,
                         b.caplen,
@@ -124,20 +786,46 @@ fn main() -> Result<()> {
                             b.ts_usec * NANOS_PER_MICRO,
                         )
                         .unwrap(),
+                        ExtractPayload::Legacy(b.data),
                     ),
                     PcapBlockOwned::NG(b) => {
                         file_type = "pcapng";
                         match b {
                             Block::EnhancedPacket(b) => {
-                                (b.origlen, b.caplen, pcapng_timestamp(b.ts_high, b.ts_low))
+                                let ts = match pcapng_timestamp_converters.get(b.if_id as usize) {
+                                    Some(convert) => convert(b.ts_high, b.ts_low),
+                                    // Packet arrived before its IDB (malformed, but
+                                    // don't panic over it); fall back to the default
+                                    // microsecond resolution.
+                                    None => make_pcapng_timestamp(6u8)(b.ts_high, b.ts_low),
+                                };
+                                (b.origlen, b.caplen, ts, ExtractPayload::Ng(b.if_id, b.data))
                             }
-                            Block::SimplePacket(_) => {
-                                panic!(
-                                    "pcapng file contains simple packets, which are unsupported"
-                                );
+                            Block::SimplePacket(b) => {
+                                // SPBs have no caplen/timestamp of their own: the
+                                // section's snaplen bounds how much was actually
+                                // captured, and we carry the previous packet's
+                                // timestamp forward rather than go without one.
+                                let capture_bytes = if current_snaplen == 0 {
+                                    b.origlen
+                                } else {
+                                    b.origlen.min(current_snaplen)
+                                };
+                                (b.origlen, capture_bytes, this_packet_ts, ExtractPayload::None)
                             }
                             Block::InterfaceDescription(i) => {
-                                pcapng_timestamp = make_pcapng_timestamp(i.if_tsresol);
+                                pcapng_timestamp_converters
+                                    .push(Box::new(make_pcapng_timestamp(i.if_tsresol)));
+                                pcapng_interfaces.push((i.linktype.0, i.snaplen, i.if_tsresol));
+                                current_snaplen = i.snaplen;
+                                reader.consume(offset);
+                                continue;
+                            }
+                            Block::SectionHeader(_) => {
+                                pcapng_timestamp_converters.clear();
+                                pcapng_interfaces.clear();
+                                current_snaplen = 0;
+                                pcapng_section += 1;
                                 reader.consume(offset);
                                 continue;
                             }
@@ -148,8 +836,10 @@ fn main() -> Result<()> {
                             }
                         }
                     }
-                    PcapBlockOwned::LegacyHeader(_) => {
+                    PcapBlockOwned::LegacyHeader(h) => {
                         file_type = "pcap";
+                        legacy_linktype = h.network.0;
+                        legacy_snaplen = h.snaplen;
                         reader.consume(offset);
                         continue;
                     }
@@ -157,16 +847,46 @@ fn main() -> Result<()> {
 
                 this_packet_ts = ts;
 
-                reader.consume(offset);
+                if extractor.is_none() {
+                    if let (Some(window), Some(write_pcap)) = (&extract_window, &cli.write_pcap) {
+                        let window_start = window.start.resolve(this_packet_ts);
+                        let window_end = window.end.resolve(this_packet_ts);
+                        extractor = Some(PacketExtractor::new(write_pcap, window_start, window_end)?);
+                    }
+                }
 
-                if previous_packet_ts == epoch_ts {
-                    first_packet_ts = this_packet_ts;
-                    previous_packet_ts = this_packet_ts;
+                if let Some(extractor) = extractor.as_mut() {
+                    if extractor.in_window(this_packet_ts) {
+                        match payload {
+                            ExtractPayload::Legacy(data) => {
+                                extractor.write_legacy_packet(
+                                    legacy_linktype,
+                                    legacy_snaplen,
+                                    this_packet_ts,
+                                    packet_bytes_capture,
+                                    packet_bytes_wire,
+                                    data,
+                                )?;
+                            }
+                            ExtractPayload::Ng(if_id, data) => {
+                                extractor.write_ng_packet(
+                                    pcapng_section,
+                                    if_id,
+                                    &pcapng_interfaces,
+                                    this_packet_ts,
+                                    packet_bytes_capture,
+                                    packet_bytes_wire,
+                                    data,
+                                )?;
+                            }
+                            ExtractPayload::None => {}
+                        }
+                    }
                 }
 
-                byte_count_capture += packet_bytes_capture;
-                byte_count_wire += packet_bytes_wire;
-                packet_count += 1;
+                reader.consume(offset);
+
+                accumulator.record(this_packet_ts, packet_bytes_wire, packet_bytes_capture);
             }
             Err(PcapError::Incomplete) => {
                 reader.refill().unwrap();
@@ -178,31 +898,142 @@ fn main() -> Result<()> {
             Err(e) => panic!("error while reading: {:?}", e),
         }
 
-        let elapsed_since_last_packet = this_packet_ts - previous_packet_ts;
+        accumulator.maybe_report(
+            sink,
+            writer,
+            this_packet_ts,
+            cli.minimum_reporting_period,
+            eof,
+        )?;
 
-        if elapsed_since_last_packet >= cli.minimum_reporting_period || (eof && packet_count > 1) {
-            let elapsed_since_last_packet_secs =
-                elapsed_since_last_packet.num_nanoseconds().unwrap() as f64 / 1e+9f64;
-            let elapsed_since_first_packet_secs = (this_packet_ts - first_packet_ts)
-                .num_microseconds()
-                .unwrap() as f64
-                / MICROS_PER_SECOND;
+        if eof {
+            break;
+        }
+    }
 
-            let rate_packets = f64::from(packet_count) / elapsed_since_last_packet_secs;
-            let rate_wire_bytes = f64::from(byte_count_wire) / elapsed_since_last_packet_secs;
-            let rate_capture_bytes = f64::from(byte_count_capture) / elapsed_since_last_packet_secs;
+    if let Some(extractor) = extractor.as_mut() {
+        extractor.finish()?;
+    }
 
-            // Gnuplot data row
-            writeln!(
-                &mut writer,
-                "{} {:.2} {:.2} {:.2}",
-                elapsed_since_first_packet_secs, rate_packets, rate_wire_bytes, rate_capture_bytes
-            )?;
+    let size =
+        Byte::from_bytes(infile.metadata().unwrap().len() as u128).get_appropriate_unit(true);
+
+    let fname = Path::new(input_filename).file_name().unwrap();
+
+    let dur = format_duration(
+        (accumulator.previous_packet_ts - accumulator.first_packet_ts)
+            .to_std()
+            .unwrap(),
+    );
+
+    sink.finish(
+        writer,
+        &format!(
+            "Packet/data rate plot for {} file {:?} ({} / {})",
+            file_type, fname, size, dur
+        ),
+    )
+}
+
+/// Re-closes a live-capture output file after a row is written: notes the
+/// position just past the last row, writes the trailer there, flushes, and
+/// truncates away anything left over from a previous (possibly longer) trailer,
+/// before seeking back to the noted position so the next row overwrites the
+/// trailer rather than following it. This keeps the file a complete,
+/// immediately runnable script (or well-formed CSV/JSON) from the first row on,
+/// rather than only once Ctrl-C is pressed.
+fn rewrite_trailer(sink: &mut dyn RowSink, writer: &mut BufWriter<&File>, title: &str) -> Result<()> {
+    let resume_at = writer.stream_position()?;
+    sink.finish(writer, title)?;
+    writer.flush()?;
+    let end = writer.stream_position()?;
+    writer.get_ref().set_len(end)?;
+    writer.seek(SeekFrom::Start(resume_at))?;
+
+    Ok(())
+}
+
+/// run_live_capture drives the rate accumulator from a live interface, flushing
+/// each `$data` row as soon as it's computed (rather than only at EOF) and
+/// re-closing the file via `rewrite_trailer` after each one, so the output is
+/// always a complete, runnable script that can be re-run against a growing
+/// capture. There is no EOF for a live capture, so this repeats until Ctrl-C is
+/// received, at which point the trailer is rewritten a final time.
+fn run_live_capture(
+    cli: &Cli,
+    device: &str,
+    filter: Option<&str>,
+    sink: &mut dyn RowSink,
+    writer: &mut BufWriter<&File>,
+) -> Result<()> {
+    let mut capture = Capture::from_device(device)
+        .context(format!("Unable to open device {}", device))?
+        .promisc(true)
+        .timeout(LIVE_CAPTURE_READ_TIMEOUT_MS)
+        .open()
+        .context(format!("Unable to start capture on device {}", device))?;
+
+    if let Some(bpf) = filter {
+        capture
+            .filter(bpf, true)
+            .context(format!("Invalid BPF filter {:?}", bpf))?;
+    }
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ctrlc::set_handler(move || {
+            interrupted.store(true, Ordering::SeqCst);
+        })
+        .context("Unable to install Ctrl-C handler")?;
+    }
 
-            previous_packet_ts = this_packet_ts;
-            packet_count = 0;
-            byte_count_wire = 0;
-            byte_count_capture = 0;
+    sink.write_header(writer, &format!("live capture on {}", device))?;
+
+    let mut accumulator = RateAccumulator::new();
+    let mut this_packet_ts = accumulator.epoch_ts;
+    let start = Instant::now();
+
+    loop {
+        let eof = interrupted.load(Ordering::SeqCst);
+
+        match capture.next_packet() {
+            Ok(packet) => {
+                let ts = NaiveDateTime::from_timestamp_opt(
+                    packet.header.ts.tv_sec as i64,
+                    (packet.header.ts.tv_usec as u32) * NANOS_PER_MICRO,
+                )
+                .unwrap();
+
+                this_packet_ts = ts;
+
+                accumulator.record(this_packet_ts, packet.header.len, packet.header.caplen);
+                let wrote_row = accumulator.maybe_report(
+                    sink,
+                    writer,
+                    this_packet_ts,
+                    cli.minimum_reporting_period,
+                    eof,
+                )?;
+
+                // Re-close the file after every row so it's always a complete,
+                // runnable script (or well-formed CSV/JSON) even if the capture
+                // runs for a long time before being interrupted.
+                if wrote_row {
+                    let title = format!(
+                        "Packet/data rate plot for live capture on {} ({})",
+                        device,
+                        format_duration(start.elapsed())
+                    );
+                    rewrite_trailer(sink, writer, &title)?;
+                }
+                writer.flush()?;
+            }
+            Err(pcap::Error::TimeoutExpired) => {
+                // No packet within the read timeout; loop back around so we notice
+                // an interrupt promptly even on an idle interface.
+            }
+            Err(e) => return Err(e).context("error while capturing"),
         }
 
         if eof {
@@ -210,47 +1041,65 @@ fn main() -> Result<()> {
         }
     }
 
-    let size =
-        Byte::from_bytes(infile.metadata().unwrap().len() as u128).get_appropriate_unit(true);
-
-    let fname = Path::new(&cli.input_filename).file_name().unwrap();
+    // The interrupt can land on an iteration where `next_packet` times out rather
+    // than returning a packet, so `maybe_report` above may never have been called
+    // with `force=true`. Flush whatever's left of the last in-progress interval
+    // before writing the trailer.
+    accumulator.maybe_report(
+        sink,
+        writer,
+        this_packet_ts,
+        cli.minimum_reporting_period,
+        true,
+    )?;
 
-    let dur = format_duration((previous_packet_ts - first_packet_ts).to_std().unwrap());
+    let title = format!(
+        "Packet/data rate plot for live capture on {} ({})",
+        device,
+        format_duration(start.elapsed())
+    );
 
-    write!(
-        &mut writer,
-        "EOD
+    rewrite_trailer(sink, writer, &title)
+}
 
-set title 'Packet/data rate plot for {} file {:?} ({} / {})'
-set xlabel 'Time'
-set ylabel 'Packet rate'
-set y2label 'Data rate'
-set format y '%.0s%cpps'
-set format y2 '%.0s%cbps'
-set ytics nomirror
-set y2tics nomirror
-set xtics time format '%tH:%tM:%tS'
-set xtics rotate by -45
-plot    $data u 1:2 with lines axis x1y1 title 'Packets/s', \\
-        $data u 1:($3*8) with lines axis x1y2 title 'Bits/s on the wire', \\
-        $data u 1:($4*8) with points axis x1y2 title 'Bits/s captured'
-pause mouse close\n",
-        file_type, fname, size, dur
-    )?;
+fn main() -> Result<()> {
+    let cli = Cli::parse();
 
-    let mut perms = metadata(&cli.output_filename)
-        .context(format!(
-            "Unable to get file permissions for {}",
-            cli.output_filename.display()
-        ))?
-        .permissions();
+    if cli.filter.is_some() && cli.device.is_none() {
+        bail!("--filter requires --device");
+    }
 
-    perms.set_mode(0o755);
+    if cli.extract.is_some() && cli.device.is_some() {
+        bail!("--extract is only supported when reading from a file (--read)");
+    }
 
-    set_permissions(&cli.output_filename, perms).context(format!(
-        "Unable to set file permissions for {}",
+    let outfile = File::create(&cli.output_filename).context(format!(
+        "Unable to open output file {}",
         cli.output_filename.display()
     ))?;
 
+    let mut writer = BufWriter::new(&outfile);
+
+    let mut sink: Box<dyn RowSink> = match cli.format {
+        OutputFormat::Gnuplot => Box::new(GnuplotSink),
+        OutputFormat::Csv => Box::new(CsvSink),
+        OutputFormat::Json => Box::new(JsonSink::new()),
+    };
+
+    match (&cli.input_filename, &cli.device) {
+        (Some(input_filename), None) => {
+            run_file_capture(&cli, input_filename, sink.as_mut(), &mut writer)?;
+        }
+        (None, Some(device)) => {
+            run_live_capture(&cli, device, cli.filter.as_deref(), sink.as_mut(), &mut writer)?;
+        }
+        (None, None) => bail!("one of --read or --device is required"),
+        (Some(_), Some(_)) => unreachable!("clap enforces --read and --device are exclusive"),
+    }
+
+    if sink.is_executable() {
+        make_output_executable(&cli.output_filename)?;
+    }
+
     Ok(())
 }